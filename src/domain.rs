@@ -1,18 +1,17 @@
-use anyhow::anyhow;
 use rust_decimal::{Decimal, dec};
 
-use crate::dto::{Transaction, TransactionType};
+use crate::dto::Transaction;
+use crate::error::Error;
 
 pub struct CashFlow {
     pub r#type: CashFlowType,
     /// Global unique id of the client
     pub client: u16,
     /// Global unique id of the transaction
-    #[allow(dead_code)]
     pub tx: u32,
     pub amount: Decimal,
-    /// Whether the cash flow is under dispute, use to check if there's a dispute request when we receive a resolve or charge back
-    pub under_dispute: bool,
+    /// Current position of the cash flow in the dispute lifecycle
+    pub state: TxState,
 }
 
 pub enum CashFlowType {
@@ -20,38 +19,67 @@ pub enum CashFlowType {
     Withdrawal,
 }
 
+impl CashFlowType {
+    /// The signed contribution `amount` makes to an account's balance: `+amount`
+    /// for a deposit, `-amount` for a withdrawal. Used both for the dispute
+    /// delta moved between `available` and `held` (disputing a deposit reverses
+    /// a credit, disputing a withdrawal reverses a debit — which is why a
+    /// disputed withdrawal can legitimately drive `held` negative) and, by the
+    /// engine, for tracking net issuance independently of `Account`'s own math.
+    pub(crate) fn signed_amount(&self, amount: Decimal) -> Decimal {
+        match self {
+            CashFlowType::Deposit => amount,
+            CashFlowType::Withdrawal => -amount,
+        }
+    }
+}
+
+/// Lifecycle of a cash flow with respect to disputes.
+///
+/// The only legal transitions are `Processed -> Disputed`, `Disputed -> Resolved`
+/// and `Disputed -> ChargedBack`. In particular `ChargedBack` is terminal: a
+/// charged back transaction can never be disputed again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxState {
+    Processed,
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
 impl TryFrom<&Transaction> for CashFlow {
-    type Error = anyhow::Error;
-
-    fn try_from(value: &Transaction) -> anyhow::Result<Self> {
-        match value.amount {
-            Some(v) if v >= dec!(0) && v.scale() <= 4 => {
-                let cash_flow_type = match value.r#type {
-                    TransactionType::Deposit => CashFlowType::Deposit,
-                    TransactionType::Withdrawal => CashFlowType::Withdrawal,
-                    _ => {
-                        log::error!("trying to convert an unsupported transaction to a cash flow");
-                        return Err(anyhow!(
-                            "a generic error occurred", // This is an internal error related to
-                                                        // wrong usage of the method, we don't want to expose these details to the
-                                                        // clients
-                        ));
-                    }
-                };
-                Ok(CashFlow {
-                    r#type: cash_flow_type,
-                    client: value.client,
-                    tx: value.tx,
-                    amount: v,
-                    under_dispute: false,
-                })
+    type Error = Error;
+
+    fn try_from(value: &Transaction) -> Result<Self, Error> {
+        let (cash_flow_type, client, tx, amount) = match *value {
+            Transaction::Deposit { client, tx, amount } => {
+                (CashFlowType::Deposit, client, tx, amount)
+            }
+            Transaction::Withdrawal { client, tx, amount } => {
+                (CashFlowType::Withdrawal, client, tx, amount)
             }
-            Some(v) if v.scale() > 4 => {
-                Err(anyhow!("tx {}: has a unsupported scale (>4)", value.tx))
+            _ => {
+                log::error!("trying to convert an unsupported transaction to a cash flow");
+                // This is an internal error related to wrong usage of the method, we
+                // don't want to expose these details to the clients
+                return Err(Error::OperationNotAllowedError);
             }
-            Some(_) => Err(anyhow!("tx {}: has a negative value", value.tx)),
-            None => Err(anyhow!("tx {}: value is missing", value.tx)),
+        };
+
+        if amount.scale() > 4 {
+            return Err(Error::UnsupportedScale(tx));
         }
+        if amount < dec!(0) {
+            return Err(Error::NegativeAmount(tx));
+        }
+
+        Ok(CashFlow {
+            r#type: cash_flow_type,
+            client,
+            tx,
+            amount,
+            state: TxState::Processed,
+        })
     }
 }
 
@@ -72,55 +100,63 @@ impl Account {
         self
     }
 
-    pub fn insert(&mut self, cf: &CashFlow) {
+    pub fn insert(&mut self, cf: &CashFlow) -> Result<(), Error> {
         match cf.r#type {
             CashFlowType::Deposit => {
                 let amount = cf.amount;
                 self.available += amount;
                 self.total = self.available + self.held;
+                Ok(())
             }
             CashFlowType::Withdrawal => {
                 let amount = cf.amount;
                 if amount <= self.available {
                     self.available -= amount;
                     self.total = self.available + self.held;
+                    Ok(())
                 } else {
-                    log::error!(
-                        "user {} does not have enough money to perform a withdraw",
-                        self.client
-                    )
+                    Err(Error::InsufficientFunds {
+                        client: self.client,
+                        tx: cf.tx,
+                    })
                 }
             }
         }
     }
 
+    /// Moves a `Processed` cash flow into dispute. Callers are expected to have
+    /// already checked `cf.state == TxState::Processed`.
     pub fn dispute(&mut self, cf: &mut CashFlow) {
-        let amount = cf.amount;
-        self.available -= amount;
-        self.held += amount;
+        let signed = cf.r#type.signed_amount(cf.amount);
+        self.available -= signed;
+        self.held += signed;
         //total remains the same as we are only moving from available to held
 
-        cf.under_dispute = true
+        cf.state = TxState::Disputed
     }
 
+    /// Resolves a `Disputed` cash flow. Callers are expected to have already
+    /// checked `cf.state == TxState::Disputed`.
     pub fn resolve(&mut self, cf: &mut CashFlow) {
-        let amount = cf.amount;
-        self.held -= amount;
-        self.available += amount;
+        let signed = cf.r#type.signed_amount(cf.amount);
+        self.held -= signed;
+        self.available += signed;
 
-        cf.under_dispute = false
+        cf.state = TxState::Resolved
     }
 
     /// A chargeback related to a transaction, if this occurs the account will be locked
-    /// preventing user to perform additional operations
+    /// preventing user to perform additional operations. Callers are expected to have
+    /// already checked `cf.state == TxState::Disputed`; once applied the cash flow is
+    /// terminal and can never be disputed again.
     pub fn chargeback(&mut self, cf: &mut CashFlow) {
-        let amount = cf.amount; // We are assuming that a dispute can lead to a negative balance (e.g., due to a subsequent
+        let signed = cf.r#type.signed_amount(cf.amount); // We are assuming that a dispute can lead to a negative balance (e.g., due to a subsequent
         // withdrawal), therefore we lock the account for the investigations
         self.locked = true;
-        self.held -= amount;
-        self.total -= amount;
+        self.held -= signed;
+        self.total -= signed;
 
-        //finally we mark the cash flow as no more under dispute
-        cf.under_dispute = false
+        //finally we mark the cash flow as charged back, terminally
+        cf.state = TxState::ChargedBack
     }
 }
@@ -2,19 +2,49 @@ use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 
 use crate::domain::Account;
+use crate::error::Error;
 
+/// A single, already-validated row of the input CSV.
+///
+/// Deserialized via `TransactionRecord`, which enforces that an `amount` is
+/// present for deposits/withdrawals and absent for dispute-family rows, so
+/// illegal combinations can never reach the engine.
 #[derive(Debug, Deserialize)]
-pub struct Transaction {
-    pub r#type: TransactionType,
-    pub client: u16,
-    pub tx: u32,
-    pub amount: Option<Decimal>,
+#[serde(try_from = "TransactionRecord")]
+pub enum Transaction {
+    Deposit { client: u16, tx: u32, amount: Decimal },
+    Withdrawal { client: u16, tx: u32, amount: Decimal },
+    Dispute { client: u16, tx: u32 },
+    Resolve { client: u16, tx: u32 },
+    Chargeback { client: u16, tx: u32 },
+}
+
+impl Transaction {
+    pub fn client(&self) -> u16 {
+        match self {
+            Transaction::Deposit { client, .. }
+            | Transaction::Withdrawal { client, .. }
+            | Transaction::Dispute { client, .. }
+            | Transaction::Resolve { client, .. }
+            | Transaction::Chargeback { client, .. } => *client,
+        }
+    }
+}
+
+/// The flat shape of a CSV row, before we know whether `amount` is legal for
+/// its `r#type`. Never used outside of deserialization.
+#[derive(Debug, Deserialize)]
+struct TransactionRecord {
+    r#type: TransactionType,
+    client: u16,
+    tx: u32,
+    amount: Option<Decimal>,
 }
 
 /// Types of allowed Transaction
 #[derive(Debug, Deserialize, PartialEq)]
 #[serde(rename_all = "lowercase")] // as our input csv is lowercase
-pub enum TransactionType {
+enum TransactionType {
     Deposit,
     Withdrawal,
     Dispute,
@@ -24,6 +54,43 @@ pub enum TransactionType {
     Chargeback,
 }
 
+impl TryFrom<TransactionRecord> for Transaction {
+    type Error = Error;
+
+    fn try_from(value: TransactionRecord) -> Result<Self, Error> {
+        match (value.r#type, value.amount) {
+            (TransactionType::Deposit, Some(amount)) => Ok(Transaction::Deposit {
+                client: value.client,
+                tx: value.tx,
+                amount,
+            }),
+            (TransactionType::Withdrawal, Some(amount)) => Ok(Transaction::Withdrawal {
+                client: value.client,
+                tx: value.tx,
+                amount,
+            }),
+            (TransactionType::Deposit, None) | (TransactionType::Withdrawal, None) => {
+                Err(Error::MissingAmount(value.tx))
+            }
+            (TransactionType::Dispute, None) => Ok(Transaction::Dispute {
+                client: value.client,
+                tx: value.tx,
+            }),
+            (TransactionType::Resolve, None) => Ok(Transaction::Resolve {
+                client: value.client,
+                tx: value.tx,
+            }),
+            (TransactionType::Chargeback, None) => Ok(Transaction::Chargeback {
+                client: value.client,
+                tx: value.tx,
+            }),
+            (TransactionType::Dispute, Some(_))
+            | (TransactionType::Resolve, Some(_))
+            | (TransactionType::Chargeback, Some(_)) => Err(Error::UnexpectedAmount(value.tx)),
+        }
+    }
+}
+
 #[derive(Serialize, Eq, PartialEq, Debug, Hash)]
 pub struct AccountResponse {
     pub client: u16,
@@ -1,35 +1,87 @@
 use anyhow::{Context, anyhow};
 use std::collections::HashMap;
 use std::io;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
 
 use csv::Trim::All;
 use csv::{ReaderBuilder, Writer};
+use rust_decimal::Decimal;
 
-use crate::domain::{Account, CashFlow};
-use crate::dto::{AccountResponse, Transaction, TransactionType};
+use crate::domain::{Account, CashFlow, TxState};
+use crate::dto::{AccountResponse, Transaction};
+use crate::error::Error;
 
-pub fn run(path: &str) -> anyhow::Result<()> {
+/// How many in-flight transactions a worker's channel may buffer before the
+/// main thread blocks on `send`, bounding memory regardless of input size.
+const WORKER_CHANNEL_CAPACITY: usize = 256;
+
+/// Streams the CSV and shards processing by client id across a fixed pool of
+/// worker threads: each client is always routed to the same worker, so a
+/// worker only ever needs its own clients' accounts and cash flows in memory,
+/// and disputes/resolves/chargebacks (which always target the same client as
+/// the original transaction) stay correct without any cross-worker locking.
+///
+/// When `verify` is set, each worker independently cross-checks its own
+/// clients' final balances against running totals it tracked as transactions
+/// were applied, and logs a diagnostic for any client whose ledger drifted.
+pub fn run(path: &str, verify: bool) -> anyhow::Result<()> {
     let mut reader = ReaderBuilder::new()
         .trim(All) // as we want to accept CSV with with whitespaces
+        .flexible(true) // dispute-family rows may omit the trailing amount field
         .from_path(path)
         .with_context(|| format!("cannot find path {}", path))?;
 
     let mut writer = Writer::from_writer(io::stdout());
 
-    let mut accounts: HashMap<u16, Account> = HashMap::new();
-    let mut cash_flows: HashMap<u32, CashFlow> = HashMap::new();
+    let worker_count = thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1);
+
+    let mut senders: Vec<SyncSender<Transaction>> = Vec::with_capacity(worker_count);
+    let mut workers: Vec<JoinHandle<Vec<AccountResponse>>> = Vec::with_capacity(worker_count);
 
-    for transaction in reader.deserialize() {
-        let record: Transaction = transaction?;
-        handle_transaction(&record, &mut accounts, &mut cash_flows)?
+    for _ in 0..worker_count {
+        let (sender, receiver) = mpsc::sync_channel(WORKER_CHANNEL_CAPACITY);
+        senders.push(sender);
+        workers.push(thread::spawn(move || worker_loop(receiver, verify)));
     }
 
-    for account in accounts
-        .into_values()
-        .map(AccountResponse::from)
-        .collect::<Vec<AccountResponse>>()
-    {
-        writer.serialize(account)?;
+    let mut unparseable_rows = 0usize;
+    for transaction in reader.deserialize::<Transaction>() {
+        // A row with a missing/stray amount is rejected at deserialize time by
+        // `TryFrom<TransactionRecord>`, surfacing here as a csv error. That's a
+        // malformed row, not a fatal one: log it and move on, the same way a
+        // malformed amount caught further downstream is handled.
+        let record = match transaction {
+            Ok(record) => record,
+            Err(err) => {
+                unparseable_rows += 1;
+                log::warn!("skipping unparseable row: {err}");
+                continue;
+            }
+        };
+        let worker = record.client() as usize % worker_count;
+        // A send can only fail if that worker's thread already exited (e.g. after
+        // hitting a fatal error of its own), in which case its error will surface
+        // when we join it below, so there is nothing else to do here.
+        let _ = senders[worker].send(record);
+    }
+    if unparseable_rows > 0 {
+        log::warn!("rejected {unparseable_rows} unparseable row(s)");
+    }
+
+    // Dropping the senders closes every worker's channel, letting `worker_loop`'s
+    // `for transaction in receiver` loop end once it has drained its backlog.
+    drop(senders);
+
+    for worker in workers {
+        let accounts = worker
+            .join()
+            .map_err(|_| anyhow!("a worker thread panicked while processing transactions"))?;
+        for account in accounts {
+            writer.serialize(account)?;
+        }
     }
 
     writer.flush()?;
@@ -37,109 +89,286 @@ pub fn run(path: &str) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Drains one worker's share of the transactions, keeping only the accounts
+/// and cash flows for the clients routed to it, and returns their final
+/// snapshots once the channel closes at EOF.
+fn worker_loop(receiver: Receiver<Transaction>, verify: bool) -> Vec<AccountResponse> {
+    let mut accounts: HashMap<u16, Account> = HashMap::new();
+    let mut cash_flows: HashMap<u32, CashFlow> = HashMap::new();
+    let mut totals = LedgerTotals::default();
+    let mut rejections = RejectionCounts::default();
+
+    for transaction in receiver {
+        handle_transaction(
+            &transaction,
+            &mut accounts,
+            &mut cash_flows,
+            &mut totals,
+            &mut rejections,
+        );
+    }
+
+    if rejections.total() > 0 {
+        log::warn!("rejected {} transaction(s): {:?}", rejections.total(), rejections);
+    }
+
+    if verify {
+        verify_conservation(&accounts, &cash_flows, &totals);
+    }
+
+    accounts.into_values().map(AccountResponse::from).collect()
+}
+
+/// Per-client running totals tracked independently of `Account`'s own
+/// bookkeeping, so the `--verify` pass can catch a regression in that
+/// bookkeeping rather than trivially agreeing with it.
+#[derive(Default)]
+struct LedgerTotals {
+    /// Net of successfully applied deposits minus withdrawals, per client.
+    net_issuance: HashMap<u16, Decimal>,
+}
+
+impl LedgerTotals {
+    fn record_applied(&mut self, cf: &CashFlow) {
+        *self.net_issuance.entry(cf.client).or_default() += cf.r#type.signed_amount(cf.amount);
+    }
+}
+
+/// Per-worker tally of rejected transactions, broken out by reason, so a run
+/// can report how many transactions were dropped and why instead of leaving
+/// that information scattered across individual log lines.
+#[derive(Default, Debug)]
+struct RejectionCounts {
+    malformed_amount: usize,
+    insufficient_funds: usize,
+    frozen_account: usize,
+    unknown_transaction: usize,
+    already_disputed: usize,
+    not_disputable: usize,
+}
+
+impl RejectionCounts {
+    fn record(&mut self, err: &Error) {
+        match err {
+            Error::UnsupportedScale(_) | Error::NegativeAmount(_) => {
+                self.malformed_amount += 1
+            }
+            Error::InsufficientFunds { .. } => self.insufficient_funds += 1,
+            Error::FrozenAccount(_) => self.frozen_account += 1,
+            Error::UnknownTransaction { .. } => self.unknown_transaction += 1,
+            Error::AlreadyDisputed(_) => self.already_disputed += 1,
+            Error::NotDisputed(_) | Error::NotDisputable { .. } => self.not_disputable += 1,
+            // Never produced on this path: missing/stray amounts are rejected at CSV
+            // deserialize time (counted separately in `run`), and `OperationNotAllowedError`
+            // signals a programming error, not a rejected transaction.
+            Error::MissingAmount(_) | Error::UnexpectedAmount(_) | Error::OperationNotAllowedError => {}
+        }
+    }
+
+    fn total(&self) -> usize {
+        self.malformed_amount
+            + self.insufficient_funds
+            + self.frozen_account
+            + self.unknown_transaction
+            + self.already_disputed
+            + self.not_disputable
+    }
+}
+
+/// Logs `err` and tallies it under `rejections`, so every rejected transaction
+/// is both visible in the logs and counted towards the run's summary.
+fn reject(rejections: &mut RejectionCounts, err: Error) {
+    rejections.record(&err);
+    log::warn!("{err}");
+}
+
+/// Checks, per client, that: the account's own `total` agrees with
+/// `available + held`; `available + held` agrees with net issuance adjusted
+/// for charged-back funds; and `held` agrees with the sum of currently
+/// disputed amounts. Logs a diagnostic naming the client and the check that
+/// drifted rather than silently producing output.
+fn verify_conservation(
+    accounts: &HashMap<u16, Account>,
+    cash_flows: &HashMap<u32, CashFlow>,
+    totals: &LedgerTotals,
+) {
+    let mut disputed_by_client: HashMap<u16, Decimal> = HashMap::new();
+    let mut charged_back_by_client: HashMap<u16, Decimal> = HashMap::new();
+    for cf in cash_flows.values() {
+        match cf.state {
+            TxState::Disputed => {
+                *disputed_by_client.entry(cf.client).or_default() += cf.r#type.signed_amount(cf.amount)
+            }
+            TxState::ChargedBack => {
+                *charged_back_by_client.entry(cf.client).or_default() +=
+                    cf.r#type.signed_amount(cf.amount)
+            }
+            TxState::Processed | TxState::Resolved => {}
+        }
+    }
+
+    for account in accounts.values() {
+        if account.total != account.available + account.held {
+            log::error!(
+                "verify: client {}: total ({}) drifted from available ({}) + held ({})",
+                account.client,
+                account.total,
+                account.available,
+                account.held
+            );
+        }
+
+        let net_issuance = totals
+            .net_issuance
+            .get(&account.client)
+            .copied()
+            .unwrap_or_default();
+        let charged_back = charged_back_by_client
+            .get(&account.client)
+            .copied()
+            .unwrap_or_default();
+        let expected = net_issuance - charged_back;
+        if account.available + account.held != expected {
+            log::error!(
+                "verify: client {}: available ({}) + held ({}) drifted from expected net issuance ({})",
+                account.client,
+                account.available,
+                account.held,
+                expected
+            );
+        }
+
+        let disputed = disputed_by_client
+            .get(&account.client)
+            .copied()
+            .unwrap_or_default();
+        if account.held != disputed {
+            log::error!(
+                "verify: client {}: held ({}) drifted from the sum of disputed amounts ({})",
+                account.client,
+                account.held,
+                disputed
+            );
+        }
+    }
+}
+
 fn handle_transaction(
     transaction: &Transaction,
     accounts: &mut HashMap<u16, Account>,
     cash_flows: &mut HashMap<u32, CashFlow>,
-) -> anyhow::Result<()> {
+    totals: &mut LedgerTotals,
+    rejections: &mut RejectionCounts,
+) {
+    let client = transaction.client();
     let account = accounts
-        .entry(transaction.client)
-        .or_insert(Account::default().client(transaction.client));
+        .entry(client)
+        .or_insert(Account::default().client(client));
 
     // When the account is locked, the customer cannot perform additional requests
     if account.locked {
-        log::warn!(
-            "tx {}: received a request for a locked account",
-            transaction.tx
-        );
-    } else {
-        // we only store transactions that are a deposit or a withdrawal to not load every entry
-        if transaction.r#type == TransactionType::Deposit
-            || transaction.r#type == TransactionType::Withdrawal
-        {
-            let cf = CashFlow::try_from(transaction)?;
-            cash_flows.insert(transaction.tx, cf);
+        reject(rejections, Error::FrozenAccount(client));
+    } else if matches!(
+        transaction,
+        Transaction::Deposit { .. } | Transaction::Withdrawal { .. }
+    ) {
+        // A malformed amount (negative, or too fine-grained) is a rejected row, not a
+        // fatal error: log it and move on to the next transaction, the same way a
+        // declined withdrawal below is handled.
+        match CashFlow::try_from(transaction) {
+            Ok(cf) => apply_cash_flow(account, cash_flows, cf, totals, rejections),
+            Err(err) => reject(rejections, err),
         }
-
-        register_transactions_for_customers(account, cash_flows, transaction)?;
+    } else {
+        register_transactions_for_customers(account, cash_flows, transaction, rejections);
     };
+}
 
-    Ok(())
+/// Applies a freshly parsed deposit/withdrawal cash flow to `account`, recording
+/// it in `cash_flows` only once it has actually been applied. A cash flow that
+/// never took effect (e.g. a withdrawal declined for insufficient funds) must
+/// never be recorded, or it could later be disputed and credit funds for a
+/// debit that never happened.
+fn apply_cash_flow(
+    account: &mut Account,
+    cash_flows: &mut HashMap<u32, CashFlow>,
+    cf: CashFlow,
+    totals: &mut LedgerTotals,
+    rejections: &mut RejectionCounts,
+) {
+    match account.insert(&cf) {
+        Ok(()) => {
+            totals.record_applied(&cf);
+            cash_flows.insert(cf.tx, cf);
+        }
+        Err(err) => reject(rejections, err),
+    }
 }
 
+/// Dispatches a dispute-family transaction (deposits/withdrawals are applied
+/// directly via `apply_cash_flow` and never reach this function).
 fn register_transactions_for_customers(
     account: &mut Account,
     cash_flows: &mut HashMap<u32, CashFlow>,
     tx: &Transaction,
-) -> anyhow::Result<()> {
-    match tx.r#type {
-        TransactionType::Deposit | TransactionType::Withdrawal => {
-            match cash_flows.get(&tx.tx) {
-                Some(cf) => account.insert(cf),
-                _ => Err(anyhow!("a generic error has occurred"))?, // this should never happen
-                                                                    // as we stored a deposit of withdrawal first into the cash flows
-            }
-        }
-        TransactionType::Dispute => {
+    rejections: &mut RejectionCounts,
+) {
+    match *tx {
+        Transaction::Deposit { .. } | Transaction::Withdrawal { .. } => unreachable!(
+            "deposits and withdrawals are applied via apply_cash_flow, not this dispatch"
+        ),
+        Transaction::Dispute { client, tx } => {
             // We assume that a dispute for a non-existing transaction can be ignored since is
             // an error from partner
-            match cash_flows.get_mut(&tx.tx) {
-                Some(cf) if cf.client == tx.client && !cf.under_dispute => {
+            match cash_flows.get_mut(&tx) {
+                Some(cf) if cf.client == client && cf.state == TxState::Processed => {
                     account.dispute(cf);
                 }
-                Some(cf) if cf.client == tx.client => {
-                    log::warn!(
-                        "tx {}: received a dispute request for a transaction that is already under dispute, discarding the request",
-                        tx.tx
-                    );
+                Some(cf) if cf.client == client && cf.state == TxState::Disputed => {
+                    reject(rejections, Error::AlreadyDisputed(tx));
                 }
-                _ => {
-                    log::warn!(
-                        "tx {}: received a dispute for a non-existing transaction or related to wrong client",
-                        tx.tx
-                    )
+                Some(cf) if cf.client == client => {
+                    reject(
+                        rejections,
+                        Error::NotDisputable {
+                            tx,
+                            state: cf.state,
+                        },
+                    );
                 }
+                _ => reject(rejections, Error::UnknownTransaction { client, tx }),
             }
         }
-        TransactionType::Resolve => {
-            handle_dispute(cash_flows, tx, |cf| account.resolve(cf), "resolve")
+        Transaction::Resolve { client, tx } => {
+            handle_dispute(cash_flows, client, tx, rejections, |cf| account.resolve(cf))
         }
-        TransactionType::Chargeback => {
-            handle_dispute(cash_flows, tx, |cf| account.chargeback(cf), "chargeback")
+        Transaction::Chargeback { client, tx } => {
+            handle_dispute(cash_flows, client, tx, rejections, |cf| {
+                account.chargeback(cf)
+            })
         }
     }
-
-    Ok(())
 }
 
 fn handle_dispute<F>(
     cash_flows_hm: &mut HashMap<u32, CashFlow>,
-    tx: &Transaction,
+    client: u16,
+    tx: u32,
+    rejections: &mut RejectionCounts,
     mut f: F,
-    r#type: &str,
 ) where
     F: FnMut(&mut CashFlow),
 {
     // We assume that if the transaction is not under dispute it is a partner error,
     // therefore we can ignore the req
-    match cash_flows_hm.get_mut(&tx.tx) {
-        Some(cf) if cf.client == tx.client && cf.under_dispute => {
+    match cash_flows_hm.get_mut(&tx) {
+        Some(cf) if cf.client == client && cf.state == TxState::Disputed => {
             f(cf);
         }
-        Some(_) => {
-            log::warn!(
-                "tx {}: received a {} request for a transaction that is not under dispute or related to wrong client",
-                tx.tx,
-                r#type
-            )
-        }
-        _ => {
-            log::warn!(
-                "tx {}: received a {} request for a transaction that does not exist",
-                tx.tx,
-                r#type
-            )
+        Some(cf) if cf.client == client => {
+            reject(rejections, Error::NotDisputed(tx));
         }
+        _ => reject(rejections, Error::UnknownTransaction { client, tx }),
     }
 }
 
@@ -148,26 +377,10 @@ mod tests {
 
     use rust_decimal::{Decimal, dec};
 
-    use crate::dto::{Transaction, TransactionType};
+    use crate::dto::Transaction;
 
     use super::*;
 
-    // helpful method to build transaction, useful also if we add additional field and we don't
-    // want to break tests
-    fn build_transaction(
-        transaction_type: TransactionType,
-        client: u16,
-        tx: u32,
-        amount: Option<Decimal>,
-    ) -> Transaction {
-        Transaction {
-            r#type: transaction_type,
-            client,
-            tx,
-            amount,
-        }
-    }
-
     fn build_account(
         client: u16,
         available: Decimal,
@@ -188,19 +401,138 @@ mod tests {
     fn test_deposit() {
         let client = 1;
 
-        let transaction = build_transaction(TransactionType::Deposit, client, 1, Some(dec!(10)));
+        let transaction = Transaction::Deposit {
+            client,
+            tx: 1,
+            amount: dec!(10),
+        };
         let cf = CashFlow::try_from(&transaction).unwrap();
 
-        let mut cash_flows = HashMap::from([(cf.tx, cf)]);
-
+        let mut cash_flows = HashMap::new();
         let mut account = build_account(client, dec!(5), dec!(0), dec!(5), false);
+        let mut totals = LedgerTotals::default();
+        let mut rejections = RejectionCounts::default();
 
-        register_transactions_for_customers(&mut account, &mut cash_flows, &transaction).unwrap();
+        apply_cash_flow(
+            &mut account,
+            &mut cash_flows,
+            cf,
+            &mut totals,
+            &mut rejections,
+        );
 
         let account_updated = build_account(client, dec!(15), dec!(0), dec!(15), false);
 
         assert_eq!(account, account_updated)
     }
 
+    #[test]
+    fn test_deposit_dispute_end_to_end() {
+        let client = 1;
+
+        let deposit = Transaction::Deposit {
+            client,
+            tx: 1,
+            amount: dec!(10),
+        };
+        let cf = CashFlow::try_from(&deposit).unwrap();
+
+        let mut cash_flows = HashMap::new();
+        let mut account = build_account(client, dec!(0), dec!(0), dec!(0), false);
+        let mut totals = LedgerTotals::default();
+        let mut rejections = RejectionCounts::default();
+
+        apply_cash_flow(
+            &mut account,
+            &mut cash_flows,
+            cf,
+            &mut totals,
+            &mut rejections,
+        );
+
+        let dispute = Transaction::Dispute { client, tx: 1 };
+        register_transactions_for_customers(&mut account, &mut cash_flows, &dispute, &mut rejections);
+
+        // disputing a deposit reverses the credit: available drops, held absorbs it
+        let account_updated = build_account(client, dec!(0), dec!(10), dec!(10), false);
+
+        assert_eq!(account, account_updated);
+        assert_eq!(cash_flows.get(&1).unwrap().state, TxState::Disputed);
+    }
+
+    #[test]
+    fn test_withdrawal_dispute_end_to_end() {
+        let client = 1;
+
+        let withdrawal = Transaction::Withdrawal {
+            client,
+            tx: 1,
+            amount: dec!(10),
+        };
+        let cf = CashFlow::try_from(&withdrawal).unwrap();
+
+        let mut cash_flows = HashMap::new();
+        let mut account = build_account(client, dec!(10), dec!(0), dec!(10), false);
+        let mut totals = LedgerTotals::default();
+        let mut rejections = RejectionCounts::default();
+
+        apply_cash_flow(
+            &mut account,
+            &mut cash_flows,
+            cf,
+            &mut totals,
+            &mut rejections,
+        );
+
+        let dispute = Transaction::Dispute { client, tx: 1 };
+        register_transactions_for_customers(&mut account, &mut cash_flows, &dispute, &mut rejections);
+
+        // disputing a withdrawal reverses the debit: available is restored while held
+        // legitimately goes negative, since the money was never actually held aside
+        let account_updated = build_account(client, dec!(10), dec!(-10), dec!(0), false);
+
+        assert_eq!(account, account_updated);
+        assert_eq!(cash_flows.get(&1).unwrap().state, TxState::Disputed);
+    }
+
+    #[test]
+    fn test_withdrawal_declined_for_insufficient_funds_is_not_recorded() {
+        let client = 1;
+
+        let withdrawal = Transaction::Withdrawal {
+            client,
+            tx: 1,
+            amount: dec!(10),
+        };
+        let cf = CashFlow::try_from(&withdrawal).unwrap();
+
+        let mut cash_flows = HashMap::new();
+        let mut account = build_account(client, dec!(0), dec!(0), dec!(0), false);
+        let mut totals = LedgerTotals::default();
+        let mut rejections = RejectionCounts::default();
+
+        apply_cash_flow(
+            &mut account,
+            &mut cash_flows,
+            cf,
+            &mut totals,
+            &mut rejections,
+        );
+
+        // the withdrawal never took effect, so it must not be disputable and the
+        // balance it would have debited stays untouched
+        assert!(cash_flows.is_empty());
+        let account_unchanged = build_account(client, dec!(0), dec!(0), dec!(0), false);
+        assert_eq!(account, account_unchanged);
+        assert_eq!(rejections.insufficient_funds, 1);
+
+        let dispute = Transaction::Dispute { client, tx: 1 };
+        register_transactions_for_customers(&mut account, &mut cash_flows, &dispute, &mut rejections);
+
+        assert_eq!(account, account_unchanged);
+        assert_eq!(rejections.unknown_transaction, 1);
+        assert_eq!(rejections.total(), 2);
+    }
+
     //TODO: add more tests
 }
@@ -1,7 +1,30 @@
 use thiserror::Error;
 
+use crate::domain::TxState;
+
 #[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     #[error("Trying to perform a invalid operation")]
     OperationNotAllowedError,
+    #[error("tx {tx}: unknown transaction for client {client}")]
+    UnknownTransaction { client: u16, tx: u32 },
+    #[error("tx {0}: already under dispute")]
+    AlreadyDisputed(u32),
+    #[error("tx {0}: is not under dispute")]
+    NotDisputed(u32),
+    #[error("tx {tx}: cannot be disputed from state {state:?}")]
+    NotDisputable { tx: u32, state: TxState },
+    #[error("client {0}: account is frozen")]
+    FrozenAccount(u16),
+    #[error("tx {tx}: client {client} has insufficient funds")]
+    InsufficientFunds { client: u16, tx: u32 },
+    #[error("tx {0}: has a unsupported scale (>4)")]
+    UnsupportedScale(u32),
+    #[error("tx {0}: has a negative value")]
+    NegativeAmount(u32),
+    #[error("tx {0}: value is missing")]
+    MissingAmount(u32),
+    #[error("tx {0}: dispute-family transactions must not carry an amount")]
+    UnexpectedAmount(u32),
 }
@@ -5,10 +5,15 @@ use clap::Parser;
 mod domain;
 mod dto;
 mod engine;
+mod error;
 
 #[derive(Parser)]
 struct Cli {
     path: String,
+    /// Cross-check final account balances against running ledger totals and
+    /// log a diagnostic for any client whose balance drifted
+    #[arg(long)]
+    verify: bool,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -16,5 +21,5 @@ fn main() -> anyhow::Result<()> {
 
     let args = Cli::parse();
 
-    run(&args.path)
+    run(&args.path, args.verify)
 }